@@ -1,96 +1,86 @@
+use crate::protocol::{parse_upload_ok, Hello, Request};
 use failure::Error;
 use log::info;
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoroshiro128PlusPlus;
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{mpsc, Arc};
-use std::thread;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::interval;
 
 pub const MB: usize = 1024 * 1024;
 pub const MEASURE: usize = 32;
 
-pub fn upload(mut stream: TcpStream, bytes: usize, len: Arc<AtomicUsize>) -> Result<f64, Error> {
-    let msg = format!("UPLOAD {} 0\r\n", bytes);
-    stream.write_all(msg.as_bytes())?;
+async fn upload(mut stream: TcpStream, bytes: usize, len: Arc<AtomicUsize>) -> Result<f64, Error> {
+    let msg = Request::Upload { bytes }.encode();
+    stream.write_all(msg.as_bytes()).await?;
     len.fetch_add(msg.len(), Ordering::AcqRel);
-    let (tx, rx) = mpsc::sync_channel(16);
-    thread::spawn(move || {
-        let mut left = bytes - msg.len();
-        while left > 0 {
-            let length = MB.min(left);
-            let mut buf: Vec<u8> = Xoroshiro128PlusPlus::from_entropy()
-                .sample_iter(&rand::distributions::Alphanumeric)
-                .map(|x| x as u8)
-                .take(length)
-                .collect();
-            if left < MB {
-                buf.push(b'\n');
-            };
-            tx.send(buf).unwrap();
-            left -= length;
-        }
-    });
-    let mut line = String::new();
     let now = Instant::now();
-    loop {
-        let buffer = rx.recv()?;
-        stream.write_all(&buffer)?;
-        let length = buffer.len();
-        len.fetch_add(length, Ordering::AcqRel);
-        if buffer.last() == Some(&b'\n') {
-            break;
+    let mut left = bytes - msg.len();
+    while left > 0 {
+        let length = MB.min(left);
+        let mut buf: Vec<u8> = Xoroshiro128PlusPlus::from_entropy()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .map(|x| x as u8)
+            .take(length)
+            .collect();
+        left -= length;
+        if left == 0 {
+            buf.push(b'\n');
         }
+        stream.write_all(&buf).await?;
+        len.fetch_add(buf.len(), Ordering::AcqRel);
     }
     let time = now.elapsed().as_micros();
     info!("Upload took {:?} seconds", time as f64 / 1000000.0);
-    let mut reader = BufReader::new(stream);
-    reader.read_line(&mut line)?;
+    let mut line = String::new();
+    BufReader::new(&mut stream).read_line(&mut line).await?;
     info!("server response: {:?}", line);
-    if !line.contains(&format!("{}", bytes)) {
-        Err(failure::format_err!(
-            "Upload was interrupted,upload {} bytes but server response: {:?}",
-            bytes,
-            line
-        ))
-    } else {
-        Ok(bytes as f64 / time as f64 * 8.0)
-    }
+    parse_upload_ok(line.trim_end(), bytes)?;
+    Ok(bytes as f64 / time as f64 * 8.0)
 }
 
-fn download(mut stream: TcpStream, bytes: usize, len: Arc<AtomicUsize>) -> Result<f64, Error> {
-    stream.write_all(format!("DOWNLOAD {}\r\n", bytes).as_bytes())?;
+async fn download(mut stream: TcpStream, bytes: usize, len: Arc<AtomicUsize>) -> Result<f64, Error> {
+    stream
+        .write_all(Request::Download { bytes }.encode().as_bytes())
+        .await?;
     let mut reader = BufReader::with_capacity(MB, stream);
     let now = Instant::now();
+    // Count this connection's own bytes for the completion check; the shared
+    // `len` is only for the aggregate `measure` loop across all connections.
+    let mut received = 0;
     loop {
-        let buffer = reader.fill_buf()?;
+        let buffer = reader.fill_buf().await?;
         let length = buffer.len();
+        let last = buffer.last().copied();
+        received += length;
         len.fetch_add(length, Ordering::AcqRel);
-        if length == 0 || buffer.last() == Some(&b'\n') {
+        if length == 0 || last == Some(b'\n') {
             break;
         }
         reader.consume(length);
     }
     let time = now.elapsed().as_micros();
     info!("Download took {:?} seconds", time as f64 / 1000000.0);
-    if len.load(Ordering::Acquire) != bytes {
+    if received != bytes {
         Err(failure::format_err!("Download was interrupted"))
     } else {
         Ok(bytes as f64 / time as f64 * 8.0)
     }
 }
 
-fn measure(bytes: usize, len: Arc<AtomicUsize>) {
+async fn measure(bytes: usize, len: Arc<AtomicUsize>) {
     let step = bytes / MEASURE;
     let mut old_len = 0;
     let mut old_time = Instant::now();
+    let mut ticker = interval(Duration::from_millis(250));
     loop {
+        ticker.tick().await;
         let new_len = len.load(Ordering::Acquire);
         let delta = new_len - old_len;
-        let elapsed = old_time.elapsed();
-        let time = elapsed.as_micros();
+        let time = old_time.elapsed().as_micros();
         if delta > step {
             info!("Speed now: {} Mbps", delta as f64 / time as f64 * 8.0);
             old_len = new_len;
@@ -99,91 +89,146 @@ fn measure(bytes: usize, len: Arc<AtomicUsize>) {
         if new_len >= bytes || time > 20_000_000 {
             break;
         }
-        thread::sleep((elapsed / 4).min(Duration::from_secs(1)));
     }
 }
 
-pub fn upload_st(stream: TcpStream, bytes: usize) -> Result<f64, Error> {
+pub async fn upload_st(stream: TcpStream, bytes: usize) -> Result<f64, Error> {
     let len = Arc::new(AtomicUsize::new(0));
-    let len1 = len.clone();
-    let handle = thread::spawn(move || upload(stream, bytes, len1));
-    measure(bytes, len);
-    Ok(handle.join().unwrap()?)
+    let handle = tokio::spawn(upload(stream, bytes, len.clone()));
+    measure(bytes, len).await;
+    handle.await.unwrap()
 }
 
-pub fn upload_mt(host: String, bytes: usize, thread: usize) -> Result<f64, Error> {
+pub async fn upload_mt(host: String, bytes: usize, thread: usize) -> Result<f64, Error> {
     let bytes = bytes / thread * thread;
     let len = Arc::new(AtomicUsize::new(0));
     let now = Instant::now();
     let mut handles = Vec::new();
     for _ in 0..thread {
         let lent = len.clone();
-        let connection = TcpStream::connect(&host)?;
-        let handle = thread::spawn(move || upload(connection, bytes / thread, lent));
-        handles.push(handle);
+        // Negotiate the HI/HELLO handshake up front before issuing commands.
+        let connection = connect(&host).await?;
+        handles.push(tokio::spawn(upload(connection, bytes / thread, lent)));
     }
-    measure(bytes, len);
+    measure(bytes, len).await;
     for h in handles {
-        h.join().unwrap()?;
+        h.await.unwrap()?;
     }
     let time = now.elapsed().as_micros();
     Ok(bytes as f64 / time as f64 * 8.0)
 }
 
-pub fn download_st(stream: TcpStream, bytes: usize) -> Result<f64, Error> {
+pub async fn download_st(stream: TcpStream, bytes: usize) -> Result<f64, Error> {
     let len = Arc::new(AtomicUsize::new(0));
-    let len1 = len.clone();
-    let handle = thread::spawn(move || download(stream, bytes, len1));
-    measure(bytes, len);
-    Ok(handle.join().unwrap()?)
+    let handle = tokio::spawn(download(stream, bytes, len.clone()));
+    measure(bytes, len).await;
+    handle.await.unwrap()
 }
 
-pub fn download_mt(host: String, bytes: usize, thread: usize) -> Result<f64, Error> {
+pub async fn download_mt(host: String, bytes: usize, thread: usize) -> Result<f64, Error> {
     let bytes = bytes / thread * thread;
     let len = Arc::new(AtomicUsize::new(0));
     let now = Instant::now();
     let mut handles = Vec::new();
     for _ in 0..thread {
         let lent = len.clone();
-        let connection = TcpStream::connect(&host)?;
-        let handle = thread::spawn(move || download(connection, bytes / thread, lent));
-        handles.push(handle);
+        // Negotiate the HI/HELLO handshake up front before issuing commands.
+        let connection = connect(&host).await?;
+        handles.push(tokio::spawn(download(connection, bytes / thread, lent)));
     }
-    measure(bytes, len);
+    measure(bytes, len).await;
     for h in handles {
-        h.join().unwrap()?;
+        h.await.unwrap()?;
     }
     let time = now.elapsed().as_micros();
     Ok(bytes as f64 / time as f64 * 8.0)
 }
 
-pub fn ping(stream: &mut TcpStream) -> Result<f64, Error> {
+pub async fn ping(stream: &mut TcpStream) -> Result<f64, Error> {
     info!("Ping Test");
     let mut line = String::new();
     info!("Send \"PING \" to server");
     let now = Instant::now();
-    stream.write_all(b"PING \r\n")?;
-    let mut reader = BufReader::new(stream);
-    reader.read_line(&mut line)?;
+    stream.write_all(Request::Ping.encode().as_bytes()).await?;
+    BufReader::new(&mut *stream).read_line(&mut line).await?;
     let elapsed = now.elapsed().as_micros();
     info!("Server response: {:?}", line);
     Ok(elapsed as f64 / 1000.0)
 }
 
-pub fn test(stream: &mut TcpStream) -> Result<(), Error> {
+pub async fn test(stream: &mut TcpStream) -> Result<(), Error> {
     info!("Test connection");
-    let mut line = String::new();
     info!("Send \"HI\" to server");
-    stream.write_all(b"HI\r\n")?;
-    let mut reader = BufReader::new(stream);
-    reader.read_line(&mut line)?;
-    info!("Server response: {:?}", line);
+    let mut line = String::new();
+    stream.write_all(Request::Hello.encode().as_bytes()).await?;
+    BufReader::new(&mut *stream).read_line(&mut line).await?;
+    let hello = Hello::parse(line.trim_end())?;
+    info!("Server version: {}", hello.version);
     Ok(())
 }
 
-pub fn connect(host: &str) -> Result<TcpStream, Error> {
+pub async fn connect(host: &str) -> Result<TcpStream, Error> {
     info!("connect to server: {}", host);
-    let mut stream = TcpStream::connect(host)?;
-    test(&mut stream)?;
+    let mut stream = TcpStream::connect(host).await?;
+    test(&mut stream).await?;
     Ok(stream)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn read_line<S>(sock: &mut S) -> String
+    where
+        S: AsyncReadExt + Unpin,
+    {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while sock.read_exact(&mut byte).await.is_ok() {
+            line.push(byte[0]);
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        String::from_utf8_lossy(&line).into_owned()
+    }
+
+    /// Minimal stand-in for a speedtest server: answers the `HI` handshake with
+    /// a `HELLO` banner, then reads a `DOWNLOAD <n>` line on each connection and
+    /// streams back exactly `n` bytes ending in `\n`.
+    async fn fake_download_server(listener: TcpListener) {
+        loop {
+            let (mut sock, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                read_line(&mut sock).await; // HI
+                sock.write_all(b"HELLO 2.1 (2.1.0)\n").await.unwrap();
+                let request = read_line(&mut sock).await; // DOWNLOAD <n>
+                let n: usize = request
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|s| s.parse().ok())
+                    .unwrap();
+                let mut payload = vec![b'x'; n];
+                *payload.last_mut().unwrap() = b'\n';
+                sock.write_all(&payload).await.unwrap();
+            });
+        }
+    }
+
+    // Regression test: with the shared `len` atomic, each spawned `download`
+    // must check its own per-connection budget, not the global counter, or a
+    // multi-connection download always reports "Download was interrupted".
+    #[tokio::test]
+    async fn download_mt_multiple_connections_succeed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(fake_download_server(listener));
+        let res = download_mt(addr.to_string(), 4096, 4).await;
+        assert!(res.is_ok(), "download_mt failed: {:?}", res.err());
+    }
+}
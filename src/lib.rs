@@ -1,6 +1,12 @@
+pub mod io;
+pub mod protocol;
+pub mod reporter;
+
+use protocol::{parse_upload_ok, Request};
+
 use log::info;
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt;
 use std::io::{BufRead, BufReader, Write};
@@ -9,7 +15,7 @@ use std::time::Instant;
 
 const MB: usize = 1024 * 1024;
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Server {
     pub lat: String,
     pub lon: String,
@@ -55,7 +61,7 @@ pub fn upload(host: &str, bytes: usize) -> Result<f64, Box<dyn Error>> {
     info!("Upload {} MB", bytes as f64 / MB as f64);
     info!("connect to server: {}", host);
     let mut stream = TcpStream::connect(host)?;
-    let ulstring = format!("UPLOAD {} 0\r\n", bytes);
+    let ulstring = Request::Upload { bytes }.encode();
     info!("send upload message: {:?}", ulstring);
     stream.write_all(ulstring.as_bytes())?;
     info!("generating random bytes");
@@ -73,6 +79,7 @@ pub fn upload(host: &str, bytes: usize) -> Result<f64, Box<dyn Error>> {
     let elapsed = now.elapsed().as_micros();
     info!("Server response: {:?}", line);
     info!("Upload took {} ms", elapsed as f64 / 1000.0);
+    parse_upload_ok(line.trim_end(), bytes)?;
     Ok(bytes as f64 / elapsed as f64 * 8.0)
 }
 
@@ -80,7 +87,7 @@ pub fn download(host: &str, bytes: usize) -> Result<f64, Box<dyn Error>> {
     info!("Download {} MB", bytes as f64 / MB as f64);
     info!("connect to server: {}", host);
     let mut stream = TcpStream::connect(host)?;
-    let dlstring = format!("DOWNLOAD {}\r\n", bytes);
+    let dlstring = Request::Download { bytes }.encode();
     info!("send download message: {:?}", dlstring);
     stream.write_all(dlstring.as_bytes())?;
     let mut reader = BufReader::with_capacity(MB, stream);
@@ -124,7 +131,7 @@ pub fn ping_server(host: &str) -> Result<f64, Box<dyn Error>> {
     let mut stream = TcpStream::connect(host)?;
     info!("Send \"HI\" to server");
     let now = Instant::now();
-    stream.write_all(b"HI\r\n")?;
+    stream.write_all(Request::Hello.encode().as_bytes())?;
     let mut reader = BufReader::new(stream);
     reader.read_line(&mut line)?;
     let elapsed = now.elapsed().as_micros();
@@ -142,7 +149,8 @@ pub fn best_server() -> Result<Server, Box<dyn Error>> {
     servers.truncate(3);
     servers.iter_mut().for_each(|s| {
         info!("ping {}", s.sponsor);
-        s.latency = ping_server(&s.host).unwrap();
+        // A slow or unreachable server sorts last instead of panicking.
+        s.latency = ping_server(&s.host).unwrap_or(f64::MAX);
         info!("{} ping result: {}ms", s.sponsor, s.latency);
     });
     servers.sort_by(|a, b| a.latency.partial_cmp(&b.latency).unwrap());
@@ -150,3 +158,99 @@ pub fn best_server() -> Result<Server, Box<dyn Error>> {
     info!("Select server {}", best.sponsor);
     Ok(best)
 }
+
+/// Aggregate statistics over a set of measurement samples.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Mean absolute difference between consecutive samples (not a standard
+    /// deviation).
+    pub jitter: f64,
+    pub median: f64,
+    pub p95: f64,
+}
+
+impl Stats {
+    /// Compute min/max/mean, jitter and the median/95th percentile over
+    /// `samples`. An empty slice yields all-zero statistics.
+    pub fn from_samples(samples: &[f64]) -> Stats {
+        if samples.is_empty() {
+            return Stats {
+                min: 0.0,
+                max: 0.0,
+                mean: 0.0,
+                jitter: 0.0,
+                median: 0.0,
+                p95: 0.0,
+            };
+        }
+        let n = samples.len();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let jitter = if n > 1 {
+            samples
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .sum::<f64>()
+                / (n - 1) as f64
+        } else {
+            0.0
+        };
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let percentile = |p: f64| {
+            let idx = ((p * (n as f64 - 1.0)).round() as usize).min(n - 1);
+            sorted[idx]
+        };
+        Stats {
+            min: sorted[0],
+            max: sorted[n - 1],
+            mean,
+            jitter,
+            median: percentile(0.5),
+            p95: percentile(0.95),
+        }
+    }
+}
+
+/// A full result record for a completed test, ready to be serialized as JSON.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub command: String,
+    pub server: Option<Server>,
+    pub samples: Vec<f64>,
+    pub stats: Stats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_over_multiple_samples() {
+        let stats = Stats::from_samples(&[10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(stats.min, 10.0);
+        assert_eq!(stats.max, 40.0);
+        assert_eq!(stats.mean, 25.0);
+        // Consecutive differences are all 10, so jitter is 10.
+        assert_eq!(stats.jitter, 10.0);
+        assert_eq!(stats.p95, 40.0);
+    }
+
+    #[test]
+    fn stats_single_sample_has_no_spread() {
+        let stats = Stats::from_samples(&[12.0]);
+        assert_eq!(stats.min, 12.0);
+        assert_eq!(stats.max, 12.0);
+        assert_eq!(stats.mean, 12.0);
+        assert_eq!(stats.jitter, 0.0);
+    }
+
+    #[test]
+    fn stats_empty_is_zero() {
+        let stats = Stats::from_samples(&[]);
+        assert_eq!(stats.mean, 0.0);
+        assert_eq!(stats.jitter, 0.0);
+    }
+}
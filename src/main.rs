@@ -1,9 +1,13 @@
 use log::{error, info, LevelFilter};
 use simplelog::*;
+use speedtest::reporter::Reporter;
 use speedtest::*;
 use std::error::Error;
 use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -28,6 +32,21 @@ struct Opt {
     /// Count of times to test
     #[structopt(short, long)]
     count: Option<usize>,
+    /// Number of parallel connections (only used in upload or download test)
+    #[structopt(short, long)]
+    threads: Option<usize>,
+    /// Output format: `text` (default) or `json`
+    #[structopt(short, long)]
+    output: Option<String>,
+    /// Stream each result to a remote collector over a WebSocket endpoint
+    #[structopt(long = "report-ws")]
+    report_ws: Option<String>,
+    /// Seconds between measurements (only used in monitor command)
+    #[structopt(long)]
+    interval: Option<u64>,
+    /// Path of CSV file to append measurements to (only used in monitor command)
+    #[structopt(long, parse(from_os_str))]
+    csv: Option<PathBuf>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -40,6 +59,8 @@ enum Command {
     Download,
     /// Ping test
     Ping,
+    /// Continuously measure the connection and log each result to a CSV file
+    Monitor,
 }
 
 impl Command {
@@ -81,6 +102,16 @@ fn main() {
     }
 }
 
+/// Escape a field for CSV output, quoting it when it contains a comma, quote
+/// or newline so free-text values (e.g. a sponsor name) can't shift columns.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
 fn run(opt: Opt) -> Result<(), Box<dyn Error>> {
     match opt.cmd {
         Command::List => {
@@ -92,44 +123,166 @@ fn run(opt: Opt) -> Result<(), Box<dyn Error>> {
                 }
             }
         }
+        Command::Monitor => {
+            let interval = Duration::from_secs(opt.interval.unwrap_or(360));
+            let path = opt
+                .csv
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("speedtest.csv"));
+            let mut file = OpenOptions::new().append(true).create(true).open(&path)?;
+            // Write the header only when the file is still empty.
+            if file.metadata()?.len() == 0 {
+                writeln!(
+                    file,
+                    "timestamp,id,sponsor,latency_ms,download_mbps,upload_mbps"
+                )?;
+            }
+            info!("Monitoring every {} seconds into {:?}", interval.as_secs(), path);
+            let mut reporter = opt.report_ws.as_deref().map(Reporter::new);
+            loop {
+                // Each iteration selects the current best server and runs a full
+                // test; all buffers are dropped when the helpers return so the
+                // process can run unattended without growing its memory. A
+                // transient network failure only skips this tick — the daemon
+                // must not tear down — so the measurement is isolated and its
+                // error logged before sleeping until the next interval.
+                let measure = || -> Result<(Server, f64, f64, f64), Box<dyn Error>> {
+                    let server = best_server()?;
+                    let latency = ping_server(&server.host)?;
+                    let download = download(&server.host, opt.bytes.unwrap_or(100 * 1024 * 1024))?;
+                    let upload = upload(&server.host, opt.bytes.unwrap_or(50 * 1024 * 1024))?;
+                    Ok((server, latency, download, upload))
+                };
+                match measure() {
+                    Ok((server, latency, download, upload)) => {
+                        let timestamp = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0);
+                        if let Err(e) = writeln!(
+                            file,
+                            "{},{},{},{},{},{}",
+                            timestamp,
+                            csv_field(&server.id),
+                            csv_field(&server.sponsor),
+                            latency,
+                            download,
+                            upload
+                        )
+                        .and_then(|_| file.flush())
+                        {
+                            error!("failed to write CSV row: {}", e);
+                        }
+                        println!(
+                            "{} {} ping={} ms download={} Mbps upload={} Mbps",
+                            timestamp, server.sponsor, latency, download, upload
+                        );
+                        if let Some(r) = reporter.as_mut() {
+                            let record = serde_json::json!({
+                                "timestamp": timestamp,
+                                "id": server.id,
+                                "sponsor": server.sponsor,
+                                "latency_ms": latency,
+                                "download_mbps": download,
+                                "upload_mbps": upload,
+                            });
+                            if let Err(e) = r.report(&record) {
+                                error!("failed to report measurement: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("measurement failed, skipping this interval: {}", e),
+                }
+                thread::sleep(interval);
+            }
+        }
         _ => {
-            // Get hostname to test. not used in `list` command.
-            let host = if opt.id.is_some() {
-                let id = opt.id.as_ref().unwrap();
+            // Get server to test. not used in `list` command. A `--host`
+            // override has no metadata, so the server is optional.
+            let server = if let Some(id) = &opt.id {
                 match list_servers()?.into_iter().find(|s| &s.id == id) {
                     Some(s) => {
                         info!("Select server: {} based on id: {}", s.sponsor, id);
-                        Ok(s.host)
+                        Some(s)
                     }
-                    None => Err(format!("Can't find server with id {}", id)),
-                }?
-            } else if opt.host.is_some() {
-                let host = opt.host.as_ref().unwrap().clone();
+                    None => return Err(format!("Can't find server with id {}", id).into()),
+                }
+            } else if let Some(host) = &opt.host {
                 info!("Select server: {} based on host settings", host);
-                host
+                None
             } else {
-                best_server()?.host
+                Some(best_server()?)
+            };
+            let host = match &server {
+                Some(s) => s.host.clone(),
+                None => opt.host.as_ref().unwrap().clone(),
             };
             // Get running count
             let count = opt
                 .count
                 .unwrap_or(if let Command::Ping = opt.cmd { 3 } else { 1 });
-            let mut result = 0.0;
+            let rt = tokio::runtime::Runtime::new()?;
+            let mut samples = Vec::with_capacity(count);
             for i in 0..count {
+                let threads = opt.threads.unwrap_or(4);
                 let res = match opt.cmd {
-                    Command::Download => download(&host, opt.bytes.unwrap_or(100 * 1024 * 1024))?,
-                    Command::Upload => upload(&host, opt.bytes.unwrap_or(50 * 1024 * 1024))?,
+                    Command::Download => {
+                        let bytes = opt.bytes.unwrap_or(100 * 1024 * 1024);
+                        if threads > 1 {
+                            rt.block_on(io::download_mt(host.clone(), bytes, threads))
+                                .map_err(|e| e.to_string())?
+                        } else {
+                            download(&host, bytes)?
+                        }
+                    }
+                    Command::Upload => {
+                        let bytes = opt.bytes.unwrap_or(50 * 1024 * 1024);
+                        if threads > 1 {
+                            rt.block_on(io::upload_mt(host.clone(), bytes, threads))
+                                .map_err(|e| e.to_string())?
+                        } else {
+                            upload(&host, bytes)?
+                        }
+                    }
                     Command::Ping => ping_server(&host)?,
                     _ => unreachable!(),
                 };
-                result += res;
+                samples.push(res);
                 info!("seq={:?} result={}", i + 1, opt.cmd.display(res));
             }
-            println!(
-                "{:?} result={}",
-                opt.cmd,
-                opt.cmd.display(result / count as f64)
-            );
+            let stats = Stats::from_samples(&samples);
+            let report = Report {
+                command: format!("{:?}", opt.cmd),
+                server,
+                samples,
+                stats,
+            };
+            if opt.output.as_deref() == Some("json") {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                let stats = &report.stats;
+                println!("{:?} result={}", opt.cmd, opt.cmd.display(stats.mean));
+                // A single sample has no spread, so skip the degenerate line.
+                if report.samples.len() > 1 {
+                    println!(
+                        "min={} max={} median={} p95={} jitter={}",
+                        opt.cmd.display(stats.min),
+                        opt.cmd.display(stats.max),
+                        opt.cmd.display(stats.median),
+                        opt.cmd.display(stats.p95),
+                        opt.cmd.display(stats.jitter),
+                    );
+                }
+            }
+            if let Some(url) = opt.report_ws.as_deref() {
+                // One-shot runs open a connection just for this single report
+                // and drop it on exit; the persistent socket only pays off for
+                // the long-running `monitor` command.
+                let mut reporter = Reporter::new(url);
+                if let Err(e) = reporter.report(&report) {
+                    error!("failed to report result: {}", e);
+                }
+            }
         }
     }
     Ok(())
@@ -0,0 +1,111 @@
+use std::fmt;
+
+/// A request in the speedtest.net line protocol.
+#[derive(Debug, Clone)]
+pub enum Request {
+    /// Initial greeting (`HI`).
+    Hello,
+    /// Latency probe (`PING `).
+    Ping,
+    /// Upload `bytes` bytes of payload to the server.
+    Upload { bytes: usize },
+    /// Download `bytes` bytes from the server.
+    Download { bytes: usize },
+}
+
+impl Request {
+    /// Serialize the request into the line to write on the wire.
+    pub fn encode(&self) -> String {
+        match self {
+            Request::Hello => "HI\r\n".to_string(),
+            Request::Ping => "PING \r\n".to_string(),
+            Request::Upload { bytes } => format!("UPLOAD {} 0\r\n", bytes),
+            Request::Download { bytes } => format!("DOWNLOAD {}\r\n", bytes),
+        }
+    }
+}
+
+/// Error returned when a server response is malformed or unexpected.
+#[derive(Debug)]
+pub struct ProtocolError(String);
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// The greeting a server sends in response to [`Request::Hello`].
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub version: String,
+}
+
+impl Hello {
+    /// Parse a `HELLO <version> ...` banner.
+    pub fn parse(line: &str) -> Result<Hello, ProtocolError> {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("HELLO") => Ok(Hello {
+                version: parts.next().unwrap_or_default().to_string(),
+            }),
+            _ => Err(ProtocolError(format!(
+                "unexpected greeting from server: {:?}",
+                line
+            ))),
+        }
+    }
+}
+
+/// Validate the `OK <bytes> <time>` acknowledgement a server sends after an
+/// upload, returning the byte count and transfer time it reports.
+pub fn parse_upload_ok(line: &str, bytes: usize) -> Result<(usize, f64), ProtocolError> {
+    let interrupted = || {
+        ProtocolError(format!(
+            "Upload was interrupted, upload {} bytes but server response: {:?}",
+            bytes, line
+        ))
+    };
+    let mut parts = line.split_whitespace();
+    if parts.next() != Some("OK") {
+        return Err(interrupted());
+    }
+    let got: usize = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(interrupted)?;
+    let time: f64 = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0.0);
+    if got != bytes {
+        return Err(interrupted());
+    }
+    Ok((got, time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_requests() {
+        assert_eq!(Request::Hello.encode(), "HI\r\n");
+        assert_eq!(Request::Ping.encode(), "PING \r\n");
+        assert_eq!(Request::Upload { bytes: 42 }.encode(), "UPLOAD 42 0\r\n");
+        assert_eq!(Request::Download { bytes: 42 }.encode(), "DOWNLOAD 42\r\n");
+    }
+
+    #[test]
+    fn parse_hello_banner() {
+        assert_eq!(Hello::parse("HELLO 2.1 (2.1.0) 2.5").unwrap().version, "2.1");
+        assert!(Hello::parse("NOPE").is_err());
+    }
+
+    #[test]
+    fn parse_upload_ack() {
+        assert_eq!(parse_upload_ok("OK 100 0.5", 100).unwrap(), (100, 0.5));
+        // Mismatched byte count or a non-OK reply is an interrupted upload.
+        assert!(parse_upload_ok("OK 99 0.5", 100).is_err());
+        assert!(parse_upload_ok("ERR", 100).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::error::Error;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{connect, Message, WebSocket};
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Owns a persistent WebSocket to a remote collector and streams serialized
+/// measurement records to it, reconnecting with exponential backoff when the
+/// connection drops. This keeps the measurement code decoupled from transport.
+pub struct Reporter {
+    url: String,
+    socket: Option<Socket>,
+}
+
+impl Reporter {
+    /// Create a reporter targeting `url`. The connection is opened lazily on
+    /// the first [`report`](Reporter::report) call.
+    pub fn new(url: &str) -> Reporter {
+        Reporter {
+            url: url.to_string(),
+            socket: None,
+        }
+    }
+
+    fn ensure_connected(&mut self) -> Result<&mut Socket, Box<dyn Error>> {
+        if self.socket.is_none() {
+            let mut wait = Duration::from_secs(1);
+            loop {
+                match connect(&self.url) {
+                    Ok((socket, _)) => {
+                        info!("connected to collector {}", self.url);
+                        self.socket = Some(socket);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("failed to connect to collector {}: {}", self.url, e);
+                        if wait >= Duration::from_secs(60) {
+                            return Err(Box::new(e));
+                        }
+                        thread::sleep(wait);
+                        wait *= 2;
+                    }
+                }
+            }
+        }
+        Ok(self.socket.as_mut().unwrap())
+    }
+
+    /// Serialize `value` as JSON and send it to the collector. On transport
+    /// failure the socket is dropped so the next call reconnects.
+    pub fn report<T: Serialize>(&mut self, value: &T) -> Result<(), Box<dyn Error>> {
+        let payload = serde_json::to_string(value)?;
+        let socket = self.ensure_connected()?;
+        if let Err(e) = socket.write_message(Message::Text(payload)) {
+            warn!("failed to send report, will reconnect: {}", e);
+            self.socket = None;
+            return Err(Box::new(e));
+        }
+        Ok(())
+    }
+}